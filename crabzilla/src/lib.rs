@@ -17,12 +17,8 @@ fn read_from_stdin() -> Value {
 }
 
 #[import_fn(name="sayHello", scope="Stdout")]
-fn say_hello(args: Vec<Value>) {
-    if let Some(string) = args.get(0) {
-        if let Value::String(string) = string {
-            println!("Hello, {}", string);
-        }
-    }
+fn say_hello(name: String) {
+    println!("Hello, {}", name);
 }
 
 #[tokio::main]
@@ -45,8 +41,29 @@ Stdout.sayHello(user);
 pub use deno_core::error::custom_error;
 pub use deno_core::error::AnyError;
 pub use deno_core::serde_json::{json, value::Value};
-use deno_core::{op_sync, resolve_path, FsModuleLoader, JsRuntime, OpFn, OpState};
+pub use deno_core::ModuleLoader;
+pub use deno_core::OpState as State;
+use deno_core::{
+    op_async,
+    op_sync,
+    resolve_path,
+    resolve_url,
+    serde_v8,
+    v8,
+    FsModuleLoader,
+    JsRuntime,
+    ModuleId,
+    ModuleSource,
+    ModuleSpecifier,
+    ModuleType,
+    OpFn,
+    OpState,
+};
 pub use import_fn::import_fn;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
 use std::rc::Rc;
 
 fn get_args(value: &Value) -> Vec<Value> {
@@ -58,11 +75,45 @@ fn get_args(value: &Value) -> Vec<Value> {
     unreachable!();
 }
 
+/// Converts a JS call argument (a `Value`) into a naturally typed Rust value, so imported
+/// functions can declare arguments like `fn add(a: f64, b: f64)` instead of indexing into
+/// `Vec<Value>` by hand. Blanket-implemented for any `Deserialize` type.
+pub trait TryFromValue: Sized {
+    fn try_from_value(value: &Value) -> Result<Self, AnyError>;
+}
+
+impl<T> TryFromValue for T
+where
+    T: deno_core::serde::de::DeserializeOwned,
+{
+    fn try_from_value(value: &Value) -> Result<Self, AnyError> {
+        deno_core::serde_json::from_value(value.clone())
+            .map_err(|error| custom_error("TypeError", error.to_string()))
+    }
+}
+
+/// Converts a Rust return value into the `Value` sent back to JS. Blanket-implemented for
+/// any `Serialize` type.
+pub trait TryIntoValue {
+    fn try_into_value(self) -> Result<Value, AnyError>;
+}
+
+impl<T> TryIntoValue for T
+where
+    T: deno_core::serde::Serialize,
+{
+    fn try_into_value(self) -> Result<Value, AnyError> {
+        deno_core::serde_json::to_value(self)
+            .map_err(|error| custom_error("TypeError", error.to_string()))
+    }
+}
+
 /// Represents an imported Rust function.
 pub struct ImportedFn {
     op_fn: Box<OpFn>,
     name: String,
     scope: Option<String>,
+    is_async: bool,
 }
 
 /// Receives a Rust function and returns a structure that can be imported in to a runtime.
@@ -79,12 +130,117 @@ where
         op_fn,
         name: name.to_string(),
         scope,
+        is_async: false,
+    }
+}
+
+/// Receives an asynchronous Rust function and returns a structure that can be imported in to a
+/// runtime. The returned future is driven by the runtime's event loop, so the JavaScript side
+/// sees a `Promise`.
+pub fn create_async_fn<F, Fut>(imported_fn: F, name: &str, scope: Option<String>) -> ImportedFn
+where
+    F: Fn(Vec<Value>) -> Fut + 'static,
+    Fut: Future<Output = Result<Value, AnyError>> + 'static,
+{
+    let op_fn = op_async(
+        move |_state: Rc<RefCell<OpState>>, value: Value, _: ()| {
+            imported_fn(get_args(&value))
+        },
+    );
+    ImportedFn {
+        op_fn,
+        name: name.to_string(),
+        scope,
+        is_async: true,
+    }
+}
+
+/// Receives a Rust function that also wants access to the runtime's shared state (see
+/// [`Runtime::with_state`]) and returns a structure that can be imported in to a runtime.
+pub fn create_sync_fn_with_state<F>(imported_fn: F, name: &str, scope: Option<String>) -> ImportedFn
+where
+    F: Fn(&mut OpState, Vec<Value>) -> Result<Value, AnyError> + 'static,
+{
+    let op_fn = op_sync(
+        move |state: &mut OpState, value: Value, _: ()| -> Result<Value, AnyError> {
+            imported_fn(state, get_args(&value))
+        },
+    );
+    ImportedFn {
+        op_fn,
+        name: name.to_string(),
+        scope,
+        is_async: false,
     }
 }
 
 struct ImportedName {
     name: String,
     scope: Option<String>,
+    is_async: bool,
+}
+
+/// Wraps another `ModuleLoader`, serving modules registered via
+/// [`Runtime::load_module_from_source`] from memory, and otherwise always delegating to it.
+/// Sources loaded through a `.json`-suffixed specifier are additionally re-tagged as
+/// synthetic `assert { type: "json" }` modules; if the inner loader's content does not parse
+/// as JSON, the load fails with a `TypeError`.
+struct CrabzillaModuleLoader {
+    inner: Rc<dyn ModuleLoader>,
+    sources: Rc<RefCell<HashMap<ModuleSpecifier, String>>>,
+}
+
+impl ModuleLoader for CrabzillaModuleLoader {
+    fn resolve(
+        &self,
+        specifier: &str,
+        referrer: &str,
+        is_main: bool,
+    ) -> Result<ModuleSpecifier, AnyError> {
+        if let Ok(specifier) = resolve_url(specifier) {
+            if self.sources.borrow().contains_key(&specifier) {
+                return Ok(specifier);
+            }
+        }
+        self.inner.resolve(specifier, referrer, is_main)
+    }
+
+    fn load(
+        &self,
+        module_specifier: &ModuleSpecifier,
+        maybe_referrer: Option<ModuleSpecifier>,
+        is_dyn_import: bool,
+    ) -> Pin<Box<dyn Future<Output = Result<ModuleSource, AnyError>>>> {
+        if let Some(code) = self.sources.borrow().get(module_specifier).cloned() {
+            let module_specifier = module_specifier.clone();
+            return Box::pin(async move {
+                Ok(ModuleSource {
+                    code,
+                    module_type: ModuleType::JavaScript,
+                    module_url_specified: module_specifier.to_string(),
+                    module_url_found: module_specifier.to_string(),
+                })
+            });
+        }
+        let is_json_specifier = module_specifier.path().ends_with(".json");
+        let inner_source = self.inner.load(module_specifier, maybe_referrer, is_dyn_import);
+        Box::pin(async move {
+            let mut source = inner_source.await?;
+            if is_json_specifier {
+                // Only the "json" type is supported for `assert { type: "..." }` imports;
+                // require the loaded source to actually be valid JSON rather than trusting
+                // the file extension alone.
+                if deno_core::serde_json::from_str::<Value>(&source.code).is_err() {
+                    return Err(custom_error(
+                        "TypeError",
+                        "Unsupported module type, expected valid JSON (supported types: \"json\")",
+                    ));
+                }
+                source.module_type = ModuleType::Json;
+            }
+            Ok(source)
+        })
+    }
 }
 
 /// Represents a JavaScript runtime instance.
@@ -92,28 +248,52 @@ pub struct Runtime {
     runtime: JsRuntime,
     imported_names: Vec<ImportedName>,
     scopes: Vec<String>,
+    module_sources: Rc<RefCell<HashMap<ModuleSpecifier, String>>>,
+    module_id: Option<ModuleId>,
 }
 
 impl Default for Runtime {
     fn default() -> Self {
+        Self::with_loader(Rc::new(FsModuleLoader))
+    }
+}
+
+impl Runtime {
+    /// Creates a new Runtime
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Creates a new Runtime that resolves modules through a custom `ModuleLoader` instead of
+    /// the default filesystem-backed loader, enabling custom resolution and loading behavior
+    /// (import maps, HTTP fetches, bundlers, and so on). In-memory modules registered with
+    /// [`Runtime::load_module_from_source`] are still served first regardless of the loader
+    /// supplied here.
+    pub fn with_loader(loader: Rc<dyn ModuleLoader>) -> Self {
+        let module_sources = Rc::new(RefCell::new(HashMap::new()));
         let runtime = JsRuntime::new(deno_core::RuntimeOptions {
-            module_loader: Some(Rc::new(FsModuleLoader)),
+            module_loader: Some(Rc::new(CrabzillaModuleLoader {
+                inner: loader,
+                sources: module_sources.clone(),
+            })),
             ..Default::default()
         });
-        let imported_names = vec![];
-        let scopes = vec![];
         Runtime {
             runtime,
-            imported_names,
-            scopes,
+            imported_names: vec![],
+            scopes: vec![],
+            module_sources,
+            module_id: None,
         }
     }
-}
 
-impl Runtime {
-    /// Creates a new Runtime
-    pub fn new() -> Self {
-        Default::default()
+    /// Inserts a value into the runtime's shared state, making it accessible from imported
+    /// functions that declare a leading `state: &mut State` parameter via `state.borrow::<T>()`
+    /// (or `state.borrow_mut::<T>()`). Useful for handles that need to persist or be shared
+    /// across calls, such as database connections, counters, or configuration.
+    pub fn with_state<T: 'static>(self, state: T) -> Self {
+        self.runtime.op_state().borrow_mut().put(state);
+        self
     }
 
     /// Imports a new ImportedFn
@@ -129,6 +309,7 @@ impl Runtime {
         self.imported_names.push(ImportedName {
             name: import_fn.name,
             scope: import_fn.scope,
+            is_async: import_fn.is_async,
         });
     }
 
@@ -144,9 +325,10 @@ impl Runtime {
                 Some(scope) => format!("window[{:?}][{:?}]", scope, import.name),
                 None => format!("window[{:?}]", import.name),
             };
+            let op_call = if import.is_async { "opAsync" } else { "opSync" };
             name_definitions.push_str(&format!(
-                "        {} = (...args) => Deno.core.opSync({:?}, {{args}});\n",
-                scope, import.name
+                "        {} = (...args) => Deno.core.{}({:?}, {{args}});\n",
+                scope, op_call, import.name
             ));
         }
         let js_source = format!(
@@ -162,10 +344,111 @@ impl Runtime {
     /// Loads a JavaScript module and evaluates it
     pub async fn load_module(&mut self, path_str: &str) -> Result<(), AnyError> {
         let specifier = resolve_path(path_str)?;
-        let id = self.runtime.load_main_module(&specifier, None).await?;
+        self.evaluate_module(&specifier).await
+    }
+
+    /// Registers an in-memory module source under `specifier` and evaluates it, so JS can be
+    /// embedded as a string (for example from a bundler or an HTTP fetch) without touching disk.
+    pub async fn load_module_from_source(
+        &mut self,
+        specifier: &str,
+        code: String,
+    ) -> Result<(), AnyError> {
+        let specifier = resolve_url(specifier)?;
+        self.module_sources.borrow_mut().insert(specifier.clone(), code);
+        self.evaluate_module(&specifier).await
+    }
+
+    async fn evaluate_module(&mut self, specifier: &ModuleSpecifier) -> Result<(), AnyError> {
+        let id = self.runtime.load_main_module(specifier, None).await?;
         let result = self.runtime.mod_evaluate(id);
         self.runtime.run_event_loop(false).await?;
-        result.await?
+        result.await?;
+        self.module_id = Some(id);
+        Ok(())
+    }
+
+    /// Calls a named export of the most recently loaded module and returns the result,
+    /// letting Rust code drive JS computations instead of only loading modules
+    /// fire-and-forget. `export_name` is looked up as a property on the module's namespace
+    /// object (so only a bare top-level export is supported, not a dotted expression like
+    /// `Foo.bar`) and invoked as a function with `args` converted via `serde_v8`. If the call
+    /// returns a `Promise` it is awaited by pumping the runtime's event loop.
+    pub async fn call(&mut self, export_name: &str, args: Vec<Value>) -> Result<Value, AnyError> {
+        let module_id = self
+            .module_id
+            .ok_or_else(|| custom_error("Error", "No module has been loaded"))?;
+        let namespace = self.runtime.get_module_namespace(module_id)?;
+        let call_result = {
+            let scope = &mut self.runtime.handle_scope();
+            let namespace = v8::Local::new(scope, namespace);
+            let key = v8::String::new(scope, export_name)
+                .ok_or_else(|| custom_error("TypeError", "Invalid export name"))?;
+            let export = namespace.get(scope, key.into()).ok_or_else(|| {
+                custom_error("TypeError", format!("\"{}\" is not exported", export_name))
+            })?;
+            let function = v8::Local::<v8::Function>::try_from(export).map_err(|_| {
+                custom_error("TypeError", format!("\"{}\" is not a function", export_name))
+            })?;
+            let arg_locals = args
+                .iter()
+                .map(|value| serde_v8::to_v8(scope, value).map_err(AnyError::from))
+                .collect::<Result<Vec<_>, AnyError>>()?;
+            let undefined = v8::undefined(scope).into();
+            let tc_scope = &mut v8::TryCatch::new(scope);
+            match function.call(tc_scope, undefined, &arg_locals) {
+                Some(result) => Ok(v8::Global::new(tc_scope, result)),
+                None => {
+                    let message = tc_scope
+                        .exception()
+                        .map(|exception| exception.to_rust_string_lossy(tc_scope))
+                        .unwrap_or_else(|| format!("\"{}\" threw", export_name));
+                    Err(custom_error("Error", message))
+                },
+            }
+        };
+        self.resolve_global(call_result?).await
+    }
+
+    /// Resolves a V8 value to a `serde_json::Value`, awaiting it first if it is a `Promise`.
+    async fn resolve_global(&mut self, global: v8::Global<v8::Value>) -> Result<Value, AnyError> {
+        let mut pumped_with_no_progress = false;
+        loop {
+            let settled = {
+                let scope = &mut self.runtime.handle_scope();
+                let local = v8::Local::new(scope, &global);
+                match v8::Local::<v8::Promise>::try_from(local) {
+                    Ok(promise) => match promise.state() {
+                        v8::PromiseState::Pending => None,
+                        v8::PromiseState::Fulfilled => {
+                            let result = promise.result(scope);
+                            Some(serde_v8::from_v8(scope, result).map_err(AnyError::from))
+                        },
+                        v8::PromiseState::Rejected => {
+                            let result = promise.result(scope);
+                            let message = serde_v8::from_v8::<Value>(scope, result)
+                                .map(|value| value.to_string())
+                                .unwrap_or_else(|_| result.to_rust_string_lossy(scope));
+                            Some(Err(custom_error("Error", message)))
+                        },
+                    },
+                    Err(_) => Some(serde_v8::from_v8(scope, local).map_err(AnyError::from)),
+                }
+            };
+            match settled {
+                Some(result) => return result,
+                None if pumped_with_no_progress => {
+                    return Err(custom_error(
+                        "Error",
+                        "Promise never settled: no pending operations left to drive it",
+                    ));
+                },
+                None => {
+                    self.runtime.run_event_loop(false).await?;
+                    pumped_with_no_progress = true;
+                },
+            }
+        }
     }
 }
 
@@ -4,8 +4,11 @@ use syn::{
     NestedMeta,
     Meta,
     Block,
+    FnArg,
     Ident,
     ItemFn,
+    Pat,
+    Type,
     parse_macro_input,
     spanned::Spanned,
     Lit,
@@ -29,37 +32,114 @@ macro_rules! option_string_to_token_stream {
     }
 }
 
-fn quote_without_return(ident: &Ident, block: &Box<Block>, crab_meta: ImportOptions) -> TokenStream {
-    let name = crab_meta.name.unwrap_or(ident.to_string());
-    let scope = option_string_to_token_stream!(crab_meta.scope);
-    let result = quote! {
-        fn #ident() -> crabzilla::ImportedFn {
-            crabzilla::create_sync_fn(
-                |args: Vec<crabzilla::Value>| -> std::result::Result<crabzilla::Value, crabzilla::AnyError> {
-                    Ok(#block)
-                },
-                #name,
-                #scope,
-            )
+/// How the imported function receives its arguments.
+enum ArgMode {
+    /// No parameters.
+    None,
+    /// The single `args: Vec<Value>` escape hatch; the block indexes into it manually.
+    RawArgs,
+    /// One or more naturally typed parameters, converted via `TryFromValue`.
+    Typed(Vec<(Ident, Type)>),
+}
+
+/// How the imported function's result should be turned into a `Value`.
+enum RetMode {
+    /// No return value; the block runs for side effects and `Value::Null` is returned.
+    None,
+    /// The block's tail expression already is a `Value`.
+    Value,
+    /// The block's tail expression is some other `Serialize` type, converted via `TryIntoValue`.
+    Typed(Type),
+}
+
+fn build_arg_bindings(params: &[(Ident, Type)]) -> proc_macro2::TokenStream {
+    let bindings = params.iter().enumerate().map(|(index, (ident, ty))| {
+        let message = format!("missing argument {} (\"{}\") of type \"{{}}\"", index, ident);
+        quote! {
+            let #ident: #ty = match args.get(#index) {
+                Some(value) => <#ty as crabzilla::TryFromValue>::try_from_value(value)?,
+                None => return Err(crabzilla::custom_error(
+                    "TypeError",
+                    format!(#message, stringify!(#ty)),
+                )),
+            };
         }
-    };
-    result.into()
+    });
+    quote! { #(#bindings)* }
+}
+
+fn build_return_tokens(ret: &RetMode, block: &Box<Block>) -> proc_macro2::TokenStream {
+    match ret {
+        RetMode::Value => quote! { Ok(#block) },
+        RetMode::None => quote! {
+            #block
+            Ok(crabzilla::Value::Null)
+        },
+        RetMode::Typed(ty) => quote! {
+            let result: #ty = #block;
+            crabzilla::TryIntoValue::try_into_value(result)
+        },
+    }
 }
 
-fn quote_with_return(ident: &Ident, block: &Box<Block>, crab_meta: ImportOptions) -> TokenStream {
+fn quote_fn(
+    ident: &Ident,
+    arg_mode: &ArgMode,
+    ret: &RetMode,
+    block: &Box<Block>,
+    crab_meta: ImportOptions,
+    is_async: bool,
+    has_state: bool,
+) -> TokenStream {
     let name = crab_meta.name.unwrap_or(ident.to_string());
     let scope = option_string_to_token_stream!(crab_meta.scope);
-    let result = quote! {
-        fn #ident() -> crabzilla::ImportedFn {
-            crabzilla::create_sync_fn(
-                |args: Vec<crabzilla::Value>| -> std::result::Result<crabzilla::Value, crabzilla::AnyError> {
-                    #block
-                    Ok(crabzilla::Value::Null)
-                },
-                #name,
-                #scope,
-            )
-        }
+    let arg_bindings = match arg_mode {
+        ArgMode::None | ArgMode::RawArgs => quote! {},
+        ArgMode::Typed(params) => build_arg_bindings(params),
+    };
+    let return_tokens = build_return_tokens(ret, block);
+    let result = match (is_async, has_state) {
+        (false, false) => quote! {
+            fn #ident() -> crabzilla::ImportedFn {
+                crabzilla::create_sync_fn(
+                    |args: Vec<crabzilla::Value>| -> std::result::Result<crabzilla::Value, crabzilla::AnyError> {
+                        #arg_bindings
+                        #return_tokens
+                    },
+                    #name,
+                    #scope,
+                )
+            }
+        },
+        (false, true) => quote! {
+            fn #ident() -> crabzilla::ImportedFn {
+                crabzilla::create_sync_fn_with_state(
+                    |state: &mut crabzilla::State, args: Vec<crabzilla::Value>| -> std::result::Result<crabzilla::Value, crabzilla::AnyError> {
+                        #arg_bindings
+                        #return_tokens
+                    },
+                    #name,
+                    #scope,
+                )
+            }
+        },
+        (true, false) => quote! {
+            fn #ident() -> crabzilla::ImportedFn {
+                crabzilla::create_async_fn(
+                    |args: Vec<crabzilla::Value>| async move {
+                        #arg_bindings
+                        #return_tokens
+                    },
+                    #name,
+                    #scope,
+                )
+            }
+        },
+        // Rejected by `import_fn` before `quote_fn` is reached (see the `is_async && has_state`
+        // check in `import_fn`); kept here only so the match stays exhaustive.
+        (true, true) => quote_spanned! {
+            ident.span() => compile_error!("async fn with state is not supported");
+        },
     };
     result.into()
 }
@@ -138,45 +218,119 @@ fn parse_meta(metas: Vec<NestedMeta>) -> Result<ImportOptions, TokenStream> {
     Ok(options)
 }
 
+/// Recognizes the opt-in `state: &mut State` leading parameter that gives an imported
+/// function access to the runtime's shared state (see `Runtime::with_state`). Both the
+/// parameter name (`state`) and the referenced type (`State` or `OpState`) must match, so an
+/// ordinary typed parameter that merely happens to be named `state` is left alone.
+fn is_state_param(arg: &FnArg) -> bool {
+    if let FnArg::Typed(pat_type) = arg {
+        if let Pat::Ident(pat_ident) = &*pat_type.pat {
+            if pat_ident.ident == "state" {
+                if let Type::Reference(type_ref) = &*pat_type.ty {
+                    if type_ref.mutability.is_some() {
+                        if let Type::Path(type_path) = &*type_ref.elem {
+                            if let Some(segment) = type_path.path.segments.last() {
+                                return segment.ident == "State" || segment.ident == "OpState";
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    false
+}
+
+/// Parses the function's parameter list (with any leading `state` parameter already
+/// stripped) into an `ArgMode`, recognizing the raw `args: Vec<Value>` escape hatch as a
+/// special case and otherwise treating each parameter as a naturally typed argument
+/// converted with `TryFromValue`.
+fn parse_arg_mode(inputs: &[FnArg]) -> Result<ArgMode, TokenStream> {
+    match quote! { #(#inputs),* }.to_string().as_str() {
+        "" => return Ok(ArgMode::None),
+        "args : Vec < Value >" |
+        "args : Vec < crabzilla :: Value >" |
+        "args : std :: vec :: Vec < Value >" |
+        "args : std :: vec :: Vec < crabzilla :: Value >" |
+        "args : :: vec :: Vec < Value >" |
+        "args : :: vec :: Vec < crabzilla :: Value >" => return Ok(ArgMode::RawArgs),
+        _ => {},
+    }
+    let mut params = Vec::with_capacity(inputs.len());
+    for arg in inputs {
+        match arg {
+            FnArg::Typed(pat_type) => {
+                match &*pat_type.pat {
+                    Pat::Ident(pat_ident) => {
+                        params.push((pat_ident.ident.clone(), (*pat_type.ty).clone()));
+                    },
+                    _ => return Err(error(
+                        &pat_type.pat,
+                        "Illegal argument, should be a plain identifier",
+                    )),
+                }
+            },
+            FnArg::Receiver(receiver) => return Err(error(
+                receiver,
+                "Illegal argument, \"self\" is not supported",
+            )),
+        }
+    }
+    Ok(ArgMode::Typed(params))
+}
+
 /// An attribute macro to convert Rust functions so they can be imported into a runtime.
 /// The meta attributes `name` and `scope` can be used to define the scoping of a particular
 /// when calling from javascript, for example `scope = "Foo", name = "bar"` would assign
 /// the function as Foo.bar. Without a scope the function will be attached to the global
 /// object, and without a name it will be assigned with the Rust function name.
+///
+/// Parameters may be written as the raw `args: Vec<Value>` escape hatch, or as naturally
+/// typed arguments (for example `a: f64, b: f64`) which are converted from the JS call's
+/// arguments via `TryFromValue`, with missing or mistyped arguments surfaced as a JS
+/// `TypeError`. Likewise the return type may be `Value`, empty, or any `Serialize` type
+/// converted via `TryIntoValue`. An optional leading `state: &mut State` parameter opts
+/// the function in to the runtime's shared state installed with `Runtime::with_state`.
+///
+/// `state` is only available on synchronous functions: the runtime shares its state across
+/// all pending calls behind a single `Rc<RefCell<OpState>>`, so a borrow held across an
+/// `.await` point could panic the moment a concurrent call touches it while this one is
+/// suspended. An `async fn` with a `state` parameter is rejected at compile time.
 #[proc_macro_attribute]
 pub fn import_fn(attr: TokenStream, item: TokenStream) -> TokenStream {
     let input = parse_macro_input!(item as ItemFn);
     let attr = parse_macro_input!(attr as AttributeArgs);
     let crab_meta = match parse_meta(attr) {
         Ok(string) => string,
-        Err(error) => return error.into(),
+        Err(error) => return error,
     };
-    match input.sig.inputs.to_token_stream().to_string().as_str() {
-        "" |
-        "args : Vec < Value >" |
-        "args : Vec < crabzilla :: Value >" => {},
-        "args : std :: vec :: Vec < Value >" => {},
-        "args : std :: vec :: Vec < crabzilla :: Value >" => {},
-        "args : :: vec :: Vec < Value >" => {},
-        "args : :: vec :: Vec < crabzilla :: Value >" => {},
-        _ => return error(
-            input.sig.inputs,
-            "Illegal arguments, should be empty or \"args: Vec<Value>\""
-        ),
+    let mut inputs: Vec<FnArg> = input.sig.inputs.iter().cloned().collect();
+    let has_state = inputs.first().map(is_state_param).unwrap_or(false);
+    if has_state {
+        inputs.remove(0);
     }
-    match input.sig.asyncness {
-        Some(_) => todo!(),
-        None => {
-            match input.sig.output.to_token_stream().to_string().as_str() {
-                "-> crabzilla :: Value" | "-> Value"
-                    => quote_without_return(&input.sig.ident, &input.block, crab_meta),
-                "-> ()" | ""
-                    => quote_with_return(&input.sig.ident, &input.block, crab_meta),
-                _ => error(
-                    input.sig.output,
-                    "Illegal return type, should be empty or \"Value\""
-                ),
-            }
-        }
+    let arg_mode = match parse_arg_mode(&inputs) {
+        Ok(arg_mode) => arg_mode,
+        Err(error) => return error,
+    };
+    let ret_mode = match input.sig.output.to_token_stream().to_string().as_str() {
+        "-> crabzilla :: Value" | "-> Value" => RetMode::Value,
+        "-> ()" | "" => RetMode::None,
+        _ => match &input.sig.output {
+            syn::ReturnType::Type(_, ty) => RetMode::Typed((**ty).clone()),
+            syn::ReturnType::Default => RetMode::None,
+        },
+    };
+    let is_async = input.sig.asyncness.is_some();
+    if is_async && has_state {
+        return error(
+            &input.sig,
+            "async fn cannot take a `state` parameter: the runtime hands out its shared state \
+             as a single `Rc<RefCell<OpState>>`, and holding a borrow of it across an `.await` \
+             point would panic (`BorrowMutError`) the moment another call touches state while \
+             this one is suspended. Keep the function synchronous, or drop the `state` \
+             parameter and manage concurrent access to your own data manually.",
+        );
     }
+    quote_fn(&input.sig.ident, &arg_mode, &ret_mode, &input.block, crab_meta, is_async, has_state)
 }